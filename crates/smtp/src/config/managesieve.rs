@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use utils::config::Config;
+
+use crate::core::ScriptDirectory;
+
+use super::ConfigContext;
+
+/// Default port assigned to ManageSieve by RFC 5804.
+pub const MANAGESIEVE_PORT: u16 = 4190;
+
+/// Listener settings for the ManageSieve server, parsed independently of
+/// [`crate::core::SieveConfig`] since it governs a separate TCP listener
+/// rather than script execution.
+pub struct ManageSieveConfig {
+    pub bind_addr: String,
+    pub bind_port: u16,
+    pub max_script_size: usize,
+    pub require_starttls: bool,
+    pub db: Option<Arc<dyn ScriptDirectory>>,
+}
+
+pub trait ConfigManageSieve {
+    fn parse_managesieve(&self, ctx: &mut ConfigContext) -> super::Result<ManageSieveConfig>;
+}
+
+impl ConfigManageSieve for Config {
+    fn parse_managesieve(&self, ctx: &mut ConfigContext) -> super::Result<ManageSieveConfig> {
+        let bind_addr = self
+            .value("managesieve.bind-addr")
+            .unwrap_or("0.0.0.0")
+            .to_string();
+        let bind_port = self
+            .property::<u16>("managesieve.bind-port")?
+            .unwrap_or(MANAGESIEVE_PORT);
+        let max_script_size = self
+            .property::<usize>("managesieve.limits.script-size")?
+            .unwrap_or(1024 * 1024);
+        let require_starttls = self
+            .property::<bool>("managesieve.starttls.require")?
+            .unwrap_or(true);
+
+        Ok(ManageSieveConfig {
+            bind_addr,
+            bind_port,
+            max_script_size,
+            require_starttls,
+            db: if let Some(db) = self.value("managesieve.use-directory") {
+                if let Some(db) = ctx.directory.directories.get(db) {
+                    Some(db.clone())
+                } else {
+                    return Err(format!(
+                        "Directory {db:?} not found for key \"managesieve.use-directory\"."
+                    ));
+                }
+            } else {
+                None
+            },
+        })
+    }
+}
@@ -21,46 +21,118 @@
  * for more details.
 */
 
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use sieve::{compiler::grammar::Capability, Compiler, Runtime};
+use sieve::{compiler::grammar::Capability, Compiler, Runtime, Sieve};
 
 use crate::core::{SieveConfig, SieveCore};
 use utils::config::{utils::AsKey, Config};
 
 use super::ConfigContext;
 
+/// Capabilities that are disabled on every runtime built by [`ConfigSieve::parse_sieve`].
+pub(crate) const DISABLED_CAPABILITIES: [Capability; 10] = [
+    Capability::FileInto,
+    Capability::Vacation,
+    Capability::VacationSeconds,
+    Capability::Fcc,
+    Capability::Mailbox,
+    Capability::MailboxId,
+    Capability::MboxMetadata,
+    Capability::ServerMetadata,
+    Capability::ImapSieve,
+    Capability::Duplicate,
+];
+
+/// Capabilities that are explicitly enabled on top of the runtime's defaults.
+pub(crate) const ENABLED_CAPABILITIES: [Capability; 2] = [Capability::Execute, Capability::Regex];
+
+/// Number of regex matching steps budgeted per unit of `sieve.limits.cpu`.
+///
+/// Regex evaluation is a DoS vector (e.g. `(a+)+$` against
+/// attacker-controlled headers), so rather than expose a separate unbounded
+/// knob, the backtracking budget scales with the script's existing CPU
+/// limit: a script already trusted to run for N instructions may spend up to
+/// N * `REGEX_STEPS_PER_CPU_UNIT` steps backtracking a pattern.
+const REGEX_STEPS_PER_CPU_UNIT: u64 = 1_000;
+
+/// `sieve.limits.cpu` equivalent used to derive the regex budget when that
+/// key isn't configured. Regex is a DoS vector whether or not an operator
+/// has set an explicit CPU limit, so `Capability::Regex` must never be
+/// enabled without *some* step budget backing it.
+const DEFAULT_REGEX_CPU_LIMIT: u64 = 50_000;
+
+/// A single entry in the `sieve.execute.programs` allowlist consumed by the
+/// `execute` Sieve action (see `crate::scripts::exec`), modelled on
+/// Pigeonhole's `extprograms` module: a logical name maps to an absolute
+/// binary, an argument template, and the bounds a script is allowed to run
+/// it under.
+#[derive(Debug, Clone)]
+pub struct ExecuteProgram {
+    pub command: PathBuf,
+    pub arguments: Vec<String>,
+    pub timeout: Duration,
+    pub allowed_exit_codes: Vec<i32>,
+}
+
 pub trait ConfigSieve {
     fn parse_sieve(&self, ctx: &mut ConfigContext) -> super::Result<SieveCore>;
+
+    /// Builds the `Sieve` compiler used both for file-based scripts loaded
+    /// here and for `PUTSCRIPT`/`CHECKSCRIPT` over ManageSieve.
+    fn build_sieve_compiler(&self) -> super::Result<Compiler>;
 }
 
+/// Fixed `with_max_*` limits passed to [`Compiler::new`] in
+/// [`ConfigSieve::build_sieve_compiler`], in call order. Shared with
+/// [`Config::sieve_compiler_fingerprint`] so the two can never drift apart:
+/// changing a limit here automatically invalidates the compiled-script
+/// cache, since it changes what the fingerprint hashes.
+const COMPILER_FIXED_LIMITS: [usize; 9] = [
+    52428800, // with_max_string_size (first call)
+    10240,    // with_max_string_size (second call, wins)
+    100,      // with_max_variable_name_size
+    50,       // with_max_nested_blocks
+    50,       // with_max_nested_tests
+    10,       // with_max_nested_foreverypart
+    128,      // with_max_local_variables
+    10240,    // with_max_header_size
+    10,       // with_max_includes
+];
+
 impl ConfigSieve for Config {
+    fn build_sieve_compiler(&self) -> super::Result<Compiler> {
+        let [string_size_a, string_size_b, var_name_size, nested_blocks, nested_tests, nested_foreverypart, local_variables, header_size, includes] =
+            COMPILER_FIXED_LIMITS;
+        let mut compiler = Compiler::new()
+            .with_max_string_size(string_size_a)
+            .with_max_string_size(string_size_b)
+            .with_max_variable_name_size(var_name_size)
+            .with_max_nested_blocks(nested_blocks)
+            .with_max_nested_tests(nested_tests)
+            .with_max_nested_foreverypart(nested_foreverypart)
+            .with_max_local_variables(local_variables)
+            .with_max_header_size(header_size)
+            .with_max_includes(includes);
+
+        if let Some(value) = self.property("sieve.limits.regex-size")? {
+            compiler = compiler.with_max_regex_size(value);
+        }
+
+        Ok(compiler)
+    }
+
     fn parse_sieve(&self, ctx: &mut ConfigContext) -> super::Result<SieveCore> {
         // Allocate compiler and runtime
-        let compiler = Compiler::new()
-            .with_max_string_size(52428800)
-            .with_max_string_size(10240)
-            .with_max_variable_name_size(100)
-            .with_max_nested_blocks(50)
-            .with_max_nested_tests(50)
-            .with_max_nested_foreverypart(10)
-            .with_max_local_variables(128)
-            .with_max_header_size(10240)
-            .with_max_includes(10);
+        let compiler = self.build_sieve_compiler()?;
         let mut runtime = Runtime::new()
-            .without_capabilities([
-                Capability::FileInto,
-                Capability::Vacation,
-                Capability::VacationSeconds,
-                Capability::Fcc,
-                Capability::Mailbox,
-                Capability::MailboxId,
-                Capability::MboxMetadata,
-                Capability::ServerMetadata,
-                Capability::ImapSieve,
-                Capability::Duplicate,
-            ])
-            .with_capability(Capability::Execute)
+            .without_capabilities(DISABLED_CAPABILITIES)
+            .with_capabilities(ENABLED_CAPABILITIES)
             .with_max_variable_size(102400)
             .with_max_header_size(10240)
             .with_valid_notification_uri("mailto")
@@ -72,9 +144,20 @@ impl ConfigSieve for Config {
         if let Some(value) = self.property("sieve.limits.out-messages")? {
             runtime.set_max_out_messages(value);
         }
-        if let Some(value) = self.property("sieve.limits.cpu")? {
+        let cpu_limit = self.property::<u64>("sieve.limits.cpu")?;
+        if let Some(value) = cpu_limit {
             runtime.set_cpu_limit(value);
         }
+        // Derive the fancy-regex step/backtrack budget from the CPU limit so
+        // a pathological pattern aborts the script with a runtime error
+        // instead of hanging the filter thread. Set unconditionally, falling
+        // back to DEFAULT_REGEX_CPU_LIMIT when sieve.limits.cpu isn't
+        // configured, so Capability::Regex is never left unbounded.
+        runtime.set_max_regex_steps(
+            cpu_limit
+                .unwrap_or(DEFAULT_REGEX_CPU_LIMIT)
+                .saturating_mul(REGEX_STEPS_PER_CPU_UNIT),
+        );
         if let Some(value) = self.property("sieve.limits.nested-includes")? {
             runtime.set_max_nested_includes(value);
         }
@@ -91,18 +174,42 @@ impl ConfigSieve for Config {
         };
         runtime.set_local_hostname(hostname.to_string());
 
-        // Parse scripts
+        // Parse scripts, reusing a cached compiled artifact when the cache is
+        // enabled and the source bytes and compiler limits haven't changed.
+        let cache = self.parse_compiled_cache()?;
         for id in self.sub_keys("sieve.scripts") {
+            // `before`/`after` hold the script chain lists parsed below.
+            if id == "before" || id == "after" {
+                continue;
+            }
             let script = self.file_contents(("sieve.scripts", id))?;
-            ctx.scripts.insert(
-                id.to_string(),
+            let compiled = if let Some(cache) = &cache {
+                cache.get_or_compile(id, &script, &compiler)?
+            } else {
                 compiler
                     .compile(&script)
                     .map_err(|err| format!("Failed to compile Sieve script {id:?}: {err}"))?
-                    .into(),
-            );
+            };
+            ctx.scripts.insert(id.to_string(), compiled.into());
         }
 
+        // Parse the global "before" and "after" script chains used in
+        // Dovecot's multiscript order: admin "before" scripts run first,
+        // then the recipient's personal script, then "after" scripts, with a
+        // `stop` in an earlier script still letting later admin scripts run.
+        let script_before = self.parse_global_scripts(ctx, "sieve.scripts.before")?;
+        let script_after = self.parse_global_scripts(ctx, "sieve.scripts.after")?;
+
+        // Parse the allowlist of external programs a script may hand the
+        // message to via `execute "name" [...]`. Only programs listed here
+        // can ever be invoked, regardless of what a script requests.
+        let execute_programs = self.parse_execute_programs()?;
+        let execute_handler = execute_programs.clone();
+        runtime.set_execute_handler(move |name: &str, args: &[String], input: &[u8]| {
+            crate::scripts::exec::execute(&execute_handler, name, args, input)
+                .map_err(|err| err.to_string())
+        });
+
         // Parse DKIM signatures
         let mut sign = Vec::new();
         for (pos, id) in self.values("sieve.sign") {
@@ -119,6 +226,7 @@ impl ConfigSieve for Config {
 
         Ok(SieveCore {
             runtime,
+            compiler: compiler.clone(),
             scripts: ctx.scripts.clone(),
             lookup: ctx.directory.lookups.clone(),
             config: SieveConfig {
@@ -146,7 +254,175 @@ impl ConfigSieve for Config {
                 } else {
                     None
                 },
+                script_before,
+                script_after,
+                execute_programs,
             },
         })
     }
 }
+
+/// On-disk cache of compiled Sieve scripts, keyed by a hash of the source
+/// bytes plus a fingerprint of the compiler limits that affect codegen.
+///
+/// Avoids recompiling every `sieve.scripts` file on each startup. Any
+/// `with_max_*` limit change in [`ConfigSieve::build_sieve_compiler`] changes
+/// the fingerprint, which changes the cache key, so stale entries are simply
+/// never matched rather than needing explicit invalidation.
+pub(crate) struct CompiledCache {
+    path: PathBuf,
+    limits_fingerprint: u64,
+}
+
+impl CompiledCache {
+    fn cache_file(&self, id: &str, script: &[u8]) -> PathBuf {
+        // `id` is an operator-controlled config sub-key, not validated
+        // against path separators — hash it into the filename rather than
+        // interpolating it directly, so a `sieve.scripts` id containing `/`
+        // or `..` can't escape `self.path`.
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        id.hash(&mut hasher);
+        script.hash(&mut hasher);
+        self.limits_fingerprint.hash(&mut hasher);
+        self.path.join(format!("{:016x}.bin", hasher.finish()))
+    }
+
+    /// Returns the cached compiled script if present and valid, otherwise
+    /// compiles it and writes the result back to the cache. A corrupt or
+    /// unreadable cache entry is silently treated as a miss: the cache is an
+    /// optimization, never a boot-time hard dependency.
+    fn get_or_compile(&self, id: &str, script: &[u8], compiler: &Compiler) -> super::Result<Sieve> {
+        let cache_file = self.cache_file(id, script);
+
+        if let Ok(bytes) = std::fs::read(&cache_file) {
+            if let Ok(sieve) = bincode::deserialize::<Sieve>(&bytes) {
+                return Ok(sieve);
+            }
+        }
+
+        let sieve = compiler
+            .compile(script)
+            .map_err(|err| format!("Failed to compile Sieve script {id:?}: {err}"))?;
+
+        if let Ok(bytes) = bincode::serialize(&sieve) {
+            let _ = std::fs::create_dir_all(&self.path);
+            let _ = std::fs::write(&cache_file, bytes);
+        }
+
+        Ok(sieve)
+    }
+}
+
+impl Config {
+    /// Builds the compiled-script cache described by `sieve.compiled-cache.*`,
+    /// or `None` when disabled.
+    fn parse_compiled_cache(&self) -> super::Result<Option<CompiledCache>> {
+        if !self
+            .property::<bool>("sieve.compiled-cache.enable")?
+            .unwrap_or(false)
+        {
+            return Ok(None);
+        }
+
+        let path = self
+            .value("sieve.compiled-cache.path")
+            .map(Path::new)
+            .ok_or_else(|| {
+                "Missing \"sieve.compiled-cache.path\" while \"sieve.compiled-cache.enable\" is true."
+                    .to_string()
+            })?
+            .to_path_buf();
+
+        Ok(Some(CompiledCache {
+            path,
+            limits_fingerprint: self.sieve_compiler_fingerprint()?,
+        }))
+    }
+
+    /// Fingerprints every `with_max_*` limit used in
+    /// [`ConfigSieve::build_sieve_compiler`], so the compiled-script cache is
+    /// invalidated automatically whenever one of them changes. Hashes
+    /// [`COMPILER_FIXED_LIMITS`] directly rather than a separate copy of the
+    /// literals, so the two can't drift apart.
+    fn sieve_compiler_fingerprint(&self) -> super::Result<u64> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        COMPILER_FIXED_LIMITS.hash(&mut hasher);
+        self.property::<usize>("sieve.limits.regex-size")?
+            .hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    /// Parses the `sieve.execute.programs.*` allowlist consulted by the
+    /// `execute` Sieve action (see [`ExecuteProgram`]). An empty allowlist
+    /// means `Capability::Execute` is effectively a no-op: every invocation
+    /// is rejected as an unknown program name.
+    fn parse_execute_programs(&self) -> super::Result<HashMap<String, ExecuteProgram>> {
+        let mut programs = HashMap::new();
+
+        for name in self.sub_keys("sieve.execute.programs") {
+            let command = self
+                .value_require(("sieve.execute.programs", name, "command"))?
+                .to_string();
+            if !Path::new(&command).is_absolute() {
+                return Err(format!(
+                    "\"command\" for execute program {name:?} must be an absolute path, got {command:?}."
+                ));
+            }
+            let arguments = self
+                .values(("sieve.execute.programs", name, "arguments"))
+                .map(|(_, arg)| arg.to_string())
+                .collect();
+            let timeout = self
+                .property::<Duration>(("sieve.execute.programs", name, "timeout"))?
+                .unwrap_or(Duration::from_secs(30));
+            let allowed_exit_codes = self
+                .values(("sieve.execute.programs", name, "allowed-exit-codes"))
+                .map(|(_, code)| {
+                    code.parse::<i32>().map_err(|_| {
+                        format!("Invalid exit code {code:?} for program {name:?}.")
+                    })
+                })
+                .collect::<super::Result<Vec<_>>>()?;
+
+            programs.insert(
+                name.to_string(),
+                ExecuteProgram {
+                    command: PathBuf::from(command),
+                    arguments,
+                    timeout,
+                    allowed_exit_codes: if allowed_exit_codes.is_empty() {
+                        vec![0]
+                    } else {
+                        allowed_exit_codes
+                    },
+                },
+            );
+        }
+
+        Ok(programs)
+    }
+
+    /// Resolves an ordered `sieve.scripts.before` / `sieve.scripts.after`
+    /// list to the script ids already compiled into `ctx.scripts`, failing
+    /// loudly if an entry references a script that was never defined under
+    /// `sieve.scripts`.
+    fn parse_global_scripts(
+        &self,
+        ctx: &ConfigContext,
+        prefix: impl AsKey,
+    ) -> super::Result<Vec<String>> {
+        let prefix = prefix.as_key();
+        self.values(prefix.as_str())
+            .map(|(pos, id)| {
+                if ctx.scripts.contains_key(id) {
+                    Ok(id.to_string())
+                } else {
+                    Err(format!(
+                        "Script {id:?} not found for key {:?}.",
+                        (prefix.as_str(), pos).as_key()
+                    ))
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{collections::HashMap, sync::Arc};
+
+use sieve::{compiler::grammar::Capability, runtime::Action, Compiler, Runtime, Sieve};
+
+use crate::config::scripts::ExecuteProgram;
+
+/// Signs outgoing messages; the signing parameters themselves live with the
+/// DKIM config loader, not here.
+pub struct DkimSigner;
+
+/// Directory-backed lookup and storage of a user's own Sieve scripts, keyed
+/// by account name. Implemented by whatever directory backend
+/// `sieve.use-directory` / `managesieve.use-directory` points at.
+pub trait ScriptDirectory: Send + Sync {
+    fn query_value(&self, account: &str, key: &str) -> Result<Option<Vec<u8>>, String>;
+    fn store_value(&self, account: &str, key: &str, value: Vec<u8>) -> Result<(), String>;
+    fn remove_value(&self, account: &str, key: &str) -> Result<(), String>;
+    /// Lists the keys stored for `account` that start with `prefix`, used by
+    /// ManageSieve's `LISTSCRIPTS` to enumerate a user's uploaded scripts.
+    fn list_keys(&self, account: &str, prefix: &str) -> Result<Vec<String>, String>;
+}
+
+pub struct SieveConfig {
+    pub from_addr: String,
+    pub from_name: String,
+    pub return_path: String,
+    pub sign: Vec<Arc<DkimSigner>>,
+    pub db: Option<Arc<dyn ScriptDirectory>>,
+    /// Admin scripts run before the recipient's personal script, in order.
+    pub script_before: Vec<String>,
+    /// Admin scripts run after the recipient's personal script, in order.
+    pub script_after: Vec<String>,
+    pub execute_programs: HashMap<String, ExecuteProgram>,
+}
+
+pub struct SieveCore {
+    pub runtime: Runtime,
+    /// The compiler scripts were built with, reused to compile a recipient's
+    /// personal script with the same limits as every `sieve.scripts` entry.
+    pub compiler: Compiler,
+    pub scripts: HashMap<String, Arc<Sieve>>,
+    pub lookup: HashMap<String, Arc<str>>,
+    pub config: SieveConfig,
+}
+
+impl SieveCore {
+    /// Runs an account's Sieve scripts in Dovecot's multiscript order: every
+    /// `script_before` entry, then the recipient's personal script (if any),
+    /// then every `script_after` entry.
+    ///
+    /// A `stop` raised inside one script only ends that script — it does not
+    /// prevent the rest of the chain from running, matching the lda-sieve
+    /// plugin's semantics. The actions that script produced before the `stop`
+    /// are kept.
+    pub async fn run_chain(&self, account: &str, message: &[u8]) -> crate::config::Result<Vec<Action>> {
+        let mut actions = Vec::new();
+
+        for id in &self.config.script_before {
+            if let Some(script) = self.scripts.get(id) {
+                actions.extend(self.run_script(script, message)?);
+            }
+        }
+
+        if let Some(script) = self.load_personal_script(account).await? {
+            actions.extend(self.run_script(&script, message)?);
+        }
+
+        for id in &self.config.script_after {
+            if let Some(script) = self.scripts.get(id) {
+                actions.extend(self.run_script(script, message)?);
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Runs a single compiled script to completion, returning the actions it
+    /// produced. A `stop` command ends evaluation of this script only.
+    fn run_script(&self, script: &Sieve, message: &[u8]) -> crate::config::Result<Vec<Action>> {
+        let mut instance = self.runtime.filter(script, message);
+        let mut actions = Vec::new();
+
+        loop {
+            match instance.run() {
+                sieve::runtime::RunResult::Action(action) => actions.push(action),
+                sieve::runtime::RunResult::Stop | sieve::runtime::RunResult::Finished => break,
+                sieve::runtime::RunResult::Error(err) => {
+                    return Err(format!("Sieve script failed at runtime: {err}"))
+                }
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Capability names the `Runtime` actually enables, queried from the
+    /// runtime itself rather than recomputing the disabled/enabled lists
+    /// from `ConfigSieve::parse_sieve` a second time, so ManageSieve's
+    /// `CAPABILITY` response can never drift from what scripts are actually
+    /// allowed to do.
+    pub fn enabled_sieve_capabilities(&self) -> Vec<String> {
+        Capability::all()
+            .iter()
+            .filter(|capability| self.runtime.has_capability(capability))
+            .map(|capability| capability.as_str().to_string())
+            .collect()
+    }
+
+    /// Fetches and compiles an account's personal active script on demand.
+    ///
+    /// Unlike `script_before`/`script_after`, which are compiled once at
+    /// startup, a user's script can change at any time via ManageSieve, so
+    /// it is always loaded fresh from `SieveConfig::db` rather than cached
+    /// on `SieveCore`.
+    async fn load_personal_script(&self, account: &str) -> crate::config::Result<Option<Sieve>> {
+        let Some(db) = &self.config.db else {
+            return Ok(None);
+        };
+
+        match db.query_value(account, "sieve_script") {
+            Ok(Some(script)) => self
+                .compiler
+                .compile(&script)
+                .map(Some)
+                .map_err(|err| format!("Failed to compile Sieve script for {account:?}: {err}")),
+            Ok(None) => Ok(None),
+            Err(err) => Err(format!(
+                "Failed to fetch Sieve script for {account:?}: {err}"
+            )),
+        }
+    }
+}
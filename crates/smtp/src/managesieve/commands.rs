@@ -0,0 +1,310 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+/// The RFC 5804 command set understood by the ManageSieve listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Capability,
+    Authenticate {
+        mechanism: String,
+        initial: Option<Vec<u8>>,
+    },
+    StartTls,
+    PutScript {
+        name: String,
+        script: Vec<u8>,
+    },
+    GetScript {
+        name: String,
+    },
+    SetActive {
+        name: String,
+    },
+    DeleteScript {
+        name: String,
+    },
+    RenameScript {
+        old_name: String,
+        new_name: String,
+    },
+    CheckScript {
+        script: Vec<u8>,
+    },
+    HaveSpace {
+        name: String,
+        size: usize,
+    },
+    ListScripts,
+    NoOp,
+    Logout,
+}
+
+/// Storage operations needed to service ManageSieve commands, backed by the
+/// per-user scripts kept in the directory (see `SieveConfig::db`).
+pub trait ScriptStore {
+    fn put_script(&self, account: &str, name: &str, script: Vec<u8>) -> Result<(), String>;
+    fn get_script(&self, account: &str, name: &str) -> Result<Vec<u8>, String>;
+    fn list_scripts(&self, account: &str) -> Result<Vec<(String, bool)>, String>;
+    fn set_active(&self, account: &str, name: &str) -> Result<(), String>;
+    fn delete_script(&self, account: &str, name: &str) -> Result<(), String>;
+    fn rename_script(&self, account: &str, old_name: &str, new_name: &str) -> Result<(), String>;
+    fn have_space(&self, account: &str, name: &str, size: usize) -> Result<(), String>;
+}
+
+/// Verifies SASL credentials presented over `AUTHENTICATE`, returning the
+/// account name to bind the session to on success.
+pub trait CredentialStore: Send + Sync {
+    fn verify_plain(&self, authcid: &str, secret: &str) -> Result<Option<String>, String>;
+}
+
+/// A ManageSieve server response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok { message: Option<String> },
+    No { message: String },
+    Bye { message: String },
+    /// `GETSCRIPT`: the script's raw bytes, followed by `OK`.
+    ScriptData(Vec<u8>),
+    /// `LISTSCRIPTS`: one `"name"[ ACTIVE]` line per script, followed by `OK`.
+    ScriptList(Vec<(String, bool)>),
+    /// `CAPABILITY`, and the post-connect greeting: one line per advertised
+    /// capability, followed by `OK`.
+    Capability {
+        lines: Vec<String>,
+        message: Option<String>,
+    },
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Response::Ok { message: None }
+    }
+
+    pub fn no(message: impl Into<String>) -> Self {
+        Response::No {
+            message: message.into(),
+        }
+    }
+
+    /// Builds a `NO` response carrying the compiler's own diagnostics, which
+    /// already include the line/column of the offending token.
+    pub fn compile_error(err: impl std::fmt::Display) -> Self {
+        Response::No {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Parses the next command out of `input`, or `Ok(None)` if it doesn't yet
+/// contain a full command (the caller should read more bytes and retry).
+///
+/// Handles the quoted-string and non-synchronizing literal (`{N+}`) forms
+/// RFC 5804 borrows from IMAP; a literal's bytes are read verbatim and never
+/// treated as further tokens.
+pub fn parse_command(input: &[u8]) -> Result<Option<(Command, usize)>, String> {
+    let Some(line_end) = find_crlf(input) else {
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(&input[..line_end]).map_err(|_| "Non-UTF8 command line")?;
+    let mut tokens = tokenize(line);
+    if tokens.is_empty() {
+        return Err("Empty command line".to_string());
+    }
+    let word = tokens.remove(0).to_ascii_uppercase();
+
+    // A trailing `{N+}` (or `{N}`) token means the command isn't finished
+    // until N more bytes (plus the terminating CRLF) have arrived.
+    let literal_len = tokens
+        .last()
+        .and_then(|t| parse_literal_marker(t))
+        .map(|n| {
+            tokens.pop();
+            n
+        });
+
+    let header_len = line_end + 2;
+    let total_len = if let Some(n) = literal_len {
+        header_len + n + 2
+    } else {
+        header_len
+    };
+    if input.len() < total_len {
+        return Ok(None);
+    }
+    let literal = literal_len.map(|n| input[header_len..header_len + n].to_vec());
+
+    let command = match word.as_str() {
+        "CAPABILITY" => Command::Capability,
+        "LOGOUT" => Command::Logout,
+        "NOOP" => Command::NoOp,
+        "STARTTLS" => Command::StartTls,
+        "LISTSCRIPTS" => Command::ListScripts,
+        "AUTHENTICATE" => Command::Authenticate {
+            mechanism: tokens.first().cloned().unwrap_or_default(),
+            initial: tokens.get(1).map(|t| t.as_bytes().to_vec()),
+        },
+        "GETSCRIPT" => Command::GetScript {
+            name: tokens.first().cloned().unwrap_or_default(),
+        },
+        "SETACTIVE" => Command::SetActive {
+            name: tokens.first().cloned().unwrap_or_default(),
+        },
+        "DELETESCRIPT" => Command::DeleteScript {
+            name: tokens.first().cloned().unwrap_or_default(),
+        },
+        "RENAMESCRIPT" => Command::RenameScript {
+            old_name: tokens.first().cloned().unwrap_or_default(),
+            new_name: tokens.get(1).cloned().unwrap_or_default(),
+        },
+        "HAVESPACE" => Command::HaveSpace {
+            name: tokens.first().cloned().unwrap_or_default(),
+            size: tokens
+                .get(1)
+                .and_then(|s| s.parse().ok())
+                .ok_or("Invalid HAVESPACE size")?,
+        },
+        "PUTSCRIPT" => Command::PutScript {
+            name: tokens.first().cloned().unwrap_or_default(),
+            script: literal.ok_or("PUTSCRIPT requires a script literal")?,
+        },
+        "CHECKSCRIPT" => Command::CheckScript {
+            script: literal.ok_or("CHECKSCRIPT requires a script literal")?,
+        },
+        other => return Err(format!("Unknown command {other:?}")),
+    };
+
+    Ok(Some((command, total_len)))
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+/// Recognizes a standalone `{N}` or `{N+}` literal length marker.
+fn parse_literal_marker(token: &str) -> Option<usize> {
+    let inner = token.strip_prefix('{')?.strip_suffix('}')?;
+    let inner = inner.strip_suffix('+').unwrap_or(inner);
+    inner.parse().ok()
+}
+
+/// Splits a command line on whitespace, treating `"..."` (with `\"` and
+/// `\\` escapes) as a single token.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            token.push(escaped);
+                        }
+                    }
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_simple_commands() {
+        let (command, consumed) = parse_command(b"CAPABILITY\r\n").unwrap().unwrap();
+        assert_eq!(command, Command::Capability);
+        assert_eq!(consumed, b"CAPABILITY\r\n".len());
+
+        let (command, _) = parse_command(b"logout\r\n").unwrap().unwrap();
+        assert_eq!(command, Command::Logout);
+    }
+
+    #[test]
+    fn parses_quoted_arguments() {
+        let (command, _) = parse_command(b"SETACTIVE \"my script\"\r\n").unwrap().unwrap();
+        assert_eq!(
+            command,
+            Command::SetActive {
+                name: "my script".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn waits_for_the_full_literal_before_parsing() {
+        assert_eq!(
+            parse_command(b"PUTSCRIPT \"test\" {5+}\r\nabc").unwrap(),
+            None
+        );
+
+        let input = b"PUTSCRIPT \"test\" {5+}\r\nhello\r\n";
+        let (command, consumed) = parse_command(input).unwrap().unwrap();
+        assert_eq!(
+            command,
+            Command::PutScript {
+                name: "test".to_string(),
+                script: b"hello".to_vec(),
+            }
+        );
+        assert_eq!(consumed, input.len());
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert!(parse_command(b"BOGUS\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_empty_line() {
+        assert!(parse_command(b"\r\n").is_err());
+    }
+
+    #[test]
+    fn incomplete_line_returns_none() {
+        assert_eq!(parse_command(b"CAPABILI").unwrap(), None);
+    }
+}
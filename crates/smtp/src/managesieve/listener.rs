@@ -0,0 +1,227 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use sieve::Compiler;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::managesieve::ManageSieveConfig;
+
+use super::{
+    commands::{parse_command, Command, CredentialStore, Response, ScriptStore},
+    session::Session,
+};
+
+/// A plaintext or TLS-upgraded connection, after `STARTTLS` swaps the
+/// former for the latter.
+enum Conn {
+    Plain(tokio::net::TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>),
+}
+
+impl Conn {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Plain(s) => s.read(buf).await,
+            Conn::Tls(s) => s.read(buf).await,
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            Conn::Plain(s) => s.write_all(buf).await,
+            Conn::Tls(s) => s.write_all(buf).await,
+        }
+    }
+}
+
+/// Accepts connections on `config.bind_addr:config.bind_port` and services
+/// the RFC 5804 command set against `store`, for as long as this task runs.
+pub async fn serve<S>(
+    config: ManageSieveConfig,
+    compiler: Compiler,
+    store: S,
+    credentials: Arc<dyn CredentialStore>,
+    capabilities: Vec<String>,
+    tls_acceptor: Option<TlsAcceptor>,
+) -> std::io::Result<()>
+where
+    S: ScriptStore + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind((config.bind_addr.as_str(), config.bind_port)).await?;
+    let max_script_size = config.max_script_size;
+
+    loop {
+        let (stream, _peer) = listener.accept().await?;
+        let require_starttls = config.require_starttls;
+        let session = Session::new(
+            compiler.clone(),
+            store.clone(),
+            credentials.clone(),
+            capabilities.clone(),
+            max_script_size,
+            tls_acceptor.is_some(),
+            require_starttls,
+        );
+        let tls_acceptor = tls_acceptor.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) =
+                handle_connection(stream, session, tls_acceptor, require_starttls).await
+            {
+                tracing::debug!("ManageSieve connection closed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: ScriptStore>(
+    stream: tokio::net::TcpStream,
+    mut session: Session<S>,
+    tls_acceptor: Option<TlsAcceptor>,
+    require_starttls: bool,
+) -> std::io::Result<()> {
+    let mut conn = Some(Conn::Plain(stream));
+    let mut buf = Vec::new();
+    let mut read_buf = [0u8; 4096];
+
+    // RFC 5804 §1: the greeting is an untagged CAPABILITY response (so a
+    // client never has to issue CAPABILITY itself just to learn STARTTLS/
+    // SASL support) followed by OK.
+    write_response(
+        conn.as_mut().unwrap(),
+        Response::Capability {
+            lines: session.capability_response(),
+            message: Some("Stalwart ManageSieve ready".to_string()),
+        },
+        &session,
+    )
+    .await?;
+
+    loop {
+        let command = loop {
+            match parse_command(&buf) {
+                Ok(Some((command, consumed))) => {
+                    buf.drain(..consumed);
+                    break command;
+                }
+                Ok(None) => {
+                    let n = conn.as_mut().unwrap().read(&mut read_buf).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    buf.extend_from_slice(&read_buf[..n]);
+                }
+                Err(err) => {
+                    write_response(conn.as_mut().unwrap(), Response::no(err), &session).await?;
+                    buf.clear();
+                    continue;
+                }
+            }
+        };
+
+        // RFC 5804 §2.2/§2.11: CAPABILITY and LOGOUT must remain available
+        // before STARTTLS, alongside STARTTLS itself.
+        let allowed_before_tls =
+            matches!(command, Command::StartTls | Command::Capability | Command::Logout);
+        if require_starttls && !session.tls_active && !allowed_before_tls {
+            write_response(
+                conn.as_mut().unwrap(),
+                Response::no("STARTTLS required before any other command"),
+                &session,
+            )
+            .await?;
+            continue;
+        }
+
+        let is_starttls = matches!(command, Command::StartTls);
+        let is_logout = matches!(command, Command::Logout);
+        let response = session.handle(command);
+        // `Session::handle` only returns `Ok` for `StartTls` when an
+        // acceptor is actually available, so this never attempts (or fakes)
+        // a handshake that isn't possible.
+        let starttls_granted = is_starttls && matches!(response, Response::Ok { .. });
+        write_response(conn.as_mut().unwrap(), response, &session).await?;
+
+        if starttls_granted {
+            match (&tls_acceptor, conn.take()) {
+                (Some(acceptor), Some(Conn::Plain(stream))) => {
+                    let tls_stream = acceptor.accept(stream).await?;
+                    session.tls_active = true;
+                    conn = Some(Conn::Tls(Box::new(tls_stream)));
+                }
+                (_, taken) => conn = taken,
+            }
+        }
+        if is_logout {
+            return Ok(());
+        }
+    }
+}
+
+async fn write_response<S: ScriptStore>(
+    conn: &mut Conn,
+    response: Response,
+    session: &Session<S>,
+) -> std::io::Result<()> {
+    match response {
+        Response::Ok { message: None } => conn.write_all(b"OK\r\n").await,
+        Response::Ok { message: Some(msg) } => {
+            conn.write_all(format!("OK \"{msg}\"\r\n").as_bytes()).await
+        }
+        Response::No { message } => conn.write_all(format!("NO \"{message}\"\r\n").as_bytes()).await,
+        Response::Bye { message } => {
+            conn.write_all(format!("BYE \"{message}\"\r\n").as_bytes())
+                .await
+        }
+        Response::ScriptData(script) => {
+            conn.write_all(format!("{{{}+}}\r\n", script.len()).as_bytes())
+                .await?;
+            conn.write_all(&script).await?;
+            conn.write_all(b"\r\nOK\r\n").await
+        }
+        Response::ScriptList(scripts) => {
+            for (name, active) in scripts {
+                let suffix = if active { " ACTIVE" } else { "" };
+                conn.write_all(format!("\"{name}\"{suffix}\r\n").as_bytes())
+                    .await?;
+            }
+            let _ = session; // capability/account context not needed for this response
+            conn.write_all(b"OK\r\n").await
+        }
+        Response::Capability { lines, message } => {
+            for line in lines {
+                conn.write_all(format!("{line}\r\n").as_bytes()).await?;
+            }
+            match message {
+                Some(msg) => conn.write_all(format!("OK \"{msg}\"\r\n").as_bytes()).await,
+                None => conn.write_all(b"OK\r\n").await,
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! RFC 5804 ManageSieve server: a TCP listener (`listener`), its wire-format
+//! command set (`commands`), per-connection state (`session`), and a
+//! directory-backed `ScriptStore`/`CredentialStore` (`store`).
+
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+
+use crate::{config::managesieve::ManageSieveConfig, core::SieveCore};
+
+use self::{
+    commands::CredentialStore,
+    store::{DirectoryCredentialStore, DirectoryScriptStore},
+};
+
+pub mod commands;
+pub mod listener;
+pub mod session;
+pub mod store;
+
+/// Builds the directory-backed store/credential adapters from `config` and
+/// spawns the listener as a background task, returning immediately. Called
+/// once from the server's startup sequence, alongside its other protocol
+/// listeners; a missing `managesieve.use-directory` just leaves the
+/// subsystem off rather than erroring.
+pub fn spawn(sieve: &SieveCore, config: ManageSieveConfig, tls_acceptor: Option<TlsAcceptor>) {
+    let Some(db) = config.db.clone() else {
+        tracing::info!(
+            "ManageSieve listener disabled: no \"managesieve.use-directory\" configured"
+        );
+        return;
+    };
+
+    let compiler = sieve.compiler.clone();
+    let capabilities = sieve.enabled_sieve_capabilities();
+    let store = DirectoryScriptStore::new(db.clone(), config.max_script_size);
+    let credentials: Arc<dyn CredentialStore> = Arc::new(DirectoryCredentialStore::new(db));
+
+    tokio::spawn(async move {
+        if let Err(err) =
+            listener::serve(config, compiler, store, credentials, capabilities, tls_acceptor).await
+        {
+            tracing::error!("ManageSieve listener failed: {err}");
+        }
+    });
+}
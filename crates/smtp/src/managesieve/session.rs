@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use sieve::Compiler;
+
+use super::commands::{Command, CredentialStore, Response, ScriptStore};
+
+/// Per-connection ManageSieve state.
+pub struct Session<S: ScriptStore> {
+    pub compiler: Compiler,
+    pub store: S,
+    pub credentials: Arc<dyn CredentialStore>,
+    pub account: Option<String>,
+    pub tls_active: bool,
+    /// Whether the listener was given a `TlsAcceptor`; `STARTTLS` only
+    /// succeeds when this is true, so a session can never claim TLS is
+    /// active when no handshake is possible.
+    tls_available: bool,
+    /// Whether `STARTTLS` must complete before `SASL` is offered, mirroring
+    /// `ManageSieveConfig::require_starttls` — kept alongside `tls_active` so
+    /// `capability_response` can tell whether SASL is *currently* usable,
+    /// not just configured.
+    require_starttls: bool,
+    /// The `SIEVE` extension names to advertise, computed once from the
+    /// shared `Runtime` (`SieveCore::enabled_sieve_capabilities`) rather than
+    /// re-derived per session.
+    capabilities: Vec<String>,
+    max_script_size: usize,
+}
+
+impl<S: ScriptStore> Session<S> {
+    pub fn new(
+        compiler: Compiler,
+        store: S,
+        credentials: Arc<dyn CredentialStore>,
+        capabilities: Vec<String>,
+        max_script_size: usize,
+        tls_available: bool,
+        require_starttls: bool,
+    ) -> Self {
+        Self {
+            compiler,
+            store,
+            credentials,
+            account: None,
+            tls_active: false,
+            tls_available,
+            require_starttls,
+            capabilities,
+            max_script_size,
+        }
+    }
+
+    pub fn authenticated(&self) -> bool {
+        self.account.is_some()
+    }
+
+    /// Advertises exactly the extensions enabled on the shared `Runtime`
+    /// (see `ConfigSieve::parse_sieve`), so `CAPABILITY` always matches what
+    /// scripts are actually allowed to do — including `STARTTLS`/`SASL`,
+    /// which only appear when they're actually usable in the session's
+    /// current state rather than unconditionally.
+    pub fn capability_response(&self) -> Vec<String> {
+        let mut lines = vec![
+            "IMPLEMENTATION \"Stalwart ManageSieve\"".to_string(),
+            format!("SIEVE \"{}\"", self.capabilities.join(" ")),
+        ];
+        // Re-issuing STARTTLS once TLS is active isn't valid, so stop
+        // advertising it; it was never valid with no acceptor configured.
+        if self.tls_available && !self.tls_active {
+            lines.push("STARTTLS".to_string());
+        }
+        // If STARTTLS is mandatory, PLAIN credentials must not be offered
+        // (and so not advertised) until the channel is actually encrypted.
+        if !self.require_starttls || self.tls_active {
+            lines.push("SASL \"PLAIN\"".to_string());
+        }
+        lines
+    }
+
+    pub fn handle(&mut self, command: Command) -> Response {
+        match command {
+            Command::Capability => Response::Capability {
+                lines: self.capability_response(),
+                message: None,
+            },
+            Command::NoOp => Response::ok(),
+            Command::Logout => Response::Bye {
+                message: "Logging out".to_string(),
+            },
+            Command::StartTls => {
+                // The actual handshake is performed by the listener, which
+                // owns the socket; it only flips `tls_active` once that
+                // handshake actually succeeds, so a missing acceptor can
+                // never leave the session silently downgraded to plaintext.
+                if self.tls_active {
+                    Response::no("TLS is already active")
+                } else if !self.tls_available {
+                    Response::no("STARTTLS is not available")
+                } else {
+                    Response::ok()
+                }
+            }
+            Command::Authenticate { mechanism, initial } => self.authenticate(&mechanism, initial),
+            Command::CheckScript { script } => match self.compiler.compile(&script) {
+                Ok(_) => Response::ok(),
+                Err(err) => Response::compile_error(err),
+            },
+            Command::PutScript { name, script } => self.with_account(|account, session| {
+                if script.len() > session.max_script_size {
+                    return Response::no(format!(
+                        "Script {name:?} exceeds maximum size of {} bytes",
+                        session.max_script_size
+                    ));
+                }
+                match session.compiler.compile(&script) {
+                    Ok(_) => match session.store.put_script(account, &name, script) {
+                        Ok(()) => Response::ok(),
+                        Err(err) => Response::no(err),
+                    },
+                    Err(err) => Response::compile_error(err),
+                }
+            }),
+            Command::GetScript { name } => self.with_account(|account, session| {
+                match session.store.get_script(account, &name) {
+                    Ok(script) => Response::ScriptData(script),
+                    Err(err) => Response::no(err),
+                }
+            }),
+            Command::ListScripts => self.with_account(|account, session| {
+                match session.store.list_scripts(account) {
+                    Ok(scripts) => Response::ScriptList(scripts),
+                    Err(err) => Response::no(err),
+                }
+            }),
+            Command::SetActive { name } => self.with_account(|account, session| {
+                match session.store.set_active(account, &name) {
+                    Ok(()) => Response::ok(),
+                    Err(err) => Response::no(err),
+                }
+            }),
+            Command::DeleteScript { name } => self.with_account(|account, session| {
+                match session.store.delete_script(account, &name) {
+                    Ok(()) => Response::ok(),
+                    Err(err) => Response::no(err),
+                }
+            }),
+            Command::RenameScript { old_name, new_name } => self.with_account(|account, session| {
+                match session.store.rename_script(account, &old_name, &new_name) {
+                    Ok(()) => Response::ok(),
+                    Err(err) => Response::no(err),
+                }
+            }),
+            Command::HaveSpace { name, size } => {
+                if size > self.max_script_size {
+                    return Response::no(format!(
+                        "Script size {size} exceeds maximum of {} bytes",
+                        self.max_script_size
+                    ));
+                }
+                self.with_account(|account, session| {
+                    match session.store.have_space(account, &name, size) {
+                        Ok(()) => Response::ok(),
+                        Err(err) => Response::no(err),
+                    }
+                })
+            }
+        }
+    }
+
+    /// Runs `f` with the authenticated account name, or returns `NO` if the
+    /// session hasn't authenticated yet.
+    fn with_account(&mut self, f: impl FnOnce(&str, &mut Self) -> Response) -> Response {
+        let Some(account) = self.account.clone() else {
+            return Response::no("Must authenticate first");
+        };
+        f(&account, self)
+    }
+
+    /// Verifies SASL credentials against the configured directory. Only
+    /// `PLAIN` (RFC 4616) is supported: `[authzid] NUL authcid NUL secret`,
+    /// base64-encoded as the initial response.
+    fn authenticate(&mut self, mechanism: &str, initial: Option<Vec<u8>>) -> Response {
+        if !mechanism.eq_ignore_ascii_case("PLAIN") {
+            return Response::no(format!("Unsupported SASL mechanism {mechanism:?}"));
+        }
+        let Some(initial) = initial else {
+            return Response::no("PLAIN requires an initial response");
+        };
+
+        let (authcid, secret) = match parse_sasl_plain(&initial) {
+            Some(parts) => parts,
+            None => return Response::no("Malformed PLAIN response"),
+        };
+
+        match self.credentials.verify_plain(&authcid, &secret) {
+            Ok(Some(account)) => {
+                self.account = Some(account);
+                Response::ok()
+            }
+            Ok(None) => Response::no("Authentication failed"),
+            Err(err) => Response::no(err),
+        }
+    }
+}
+
+/// Decodes a base64 `PLAIN` initial response into `(authcid, secret)`.
+fn parse_sasl_plain(initial: &[u8]) -> Option<(String, String)> {
+    let decoded = base64_decode(initial)?;
+    let mut parts = decoded.split(|&b| b == 0);
+    let _authzid = parts.next()?;
+    let authcid = parts.next()?;
+    let secret = parts.next()?;
+    Some((
+        String::from_utf8(authcid.to_vec()).ok()?,
+        String::from_utf8(secret.to_vec()).ok()?,
+    ))
+}
+
+fn base64_decode(input: &[u8]) -> Option<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.decode(input).ok()
+}
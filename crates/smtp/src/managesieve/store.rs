@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use crate::core::ScriptDirectory;
+
+use super::commands::{CredentialStore, ScriptStore};
+
+/// Key prefix a script's contents are stored under, keyed by name.
+const SCRIPT_PREFIX: &str = "managesieve.script.";
+/// Key the active script's *name* is tracked under.
+const ACTIVE_NAME_KEY: &str = "managesieve.active";
+/// Key the active script's *contents* are mirrored to, the same key
+/// `SieveCore::load_personal_script` reads for per-message execution.
+const ACTIVE_SCRIPT_KEY: &str = "sieve_script";
+
+/// `ScriptStore` backed by the directory configured under
+/// `managesieve.use-directory`. Activating a script over ManageSieve mirrors
+/// its contents onto the `sieve_script` key, so the change takes effect on
+/// the very next message without any extra wiring between the two
+/// subsystems.
+#[derive(Clone)]
+pub struct DirectoryScriptStore {
+    db: Arc<dyn ScriptDirectory>,
+    max_script_size: usize,
+}
+
+impl DirectoryScriptStore {
+    pub fn new(db: Arc<dyn ScriptDirectory>, max_script_size: usize) -> Self {
+        Self { db, max_script_size }
+    }
+
+    fn script_key(name: &str) -> String {
+        format!("{SCRIPT_PREFIX}{name}")
+    }
+
+    fn active_name(&self, account: &str) -> Result<Option<String>, String> {
+        Ok(self
+            .db
+            .query_value(account, ACTIVE_NAME_KEY)?
+            .and_then(|bytes| String::from_utf8(bytes).ok()))
+    }
+}
+
+impl ScriptStore for DirectoryScriptStore {
+    fn put_script(&self, account: &str, name: &str, script: Vec<u8>) -> Result<(), String> {
+        self.db.store_value(account, &Self::script_key(name), script)
+    }
+
+    fn get_script(&self, account: &str, name: &str) -> Result<Vec<u8>, String> {
+        self.db
+            .query_value(account, &Self::script_key(name))?
+            .ok_or_else(|| format!("No such script {name:?}"))
+    }
+
+    fn list_scripts(&self, account: &str) -> Result<Vec<(String, bool)>, String> {
+        let active = self.active_name(account)?;
+        let keys = self.db.list_keys(account, SCRIPT_PREFIX)?;
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let name = key.trim_start_matches(SCRIPT_PREFIX).to_string();
+                let is_active = active.as_deref() == Some(name.as_str());
+                (name, is_active)
+            })
+            .collect())
+    }
+
+    fn set_active(&self, account: &str, name: &str) -> Result<(), String> {
+        let script = self.get_script(account, name)?;
+        self.db
+            .store_value(account, ACTIVE_NAME_KEY, name.as_bytes().to_vec())?;
+        self.db.store_value(account, ACTIVE_SCRIPT_KEY, script)
+    }
+
+    fn delete_script(&self, account: &str, name: &str) -> Result<(), String> {
+        if self.active_name(account)?.as_deref() == Some(name) {
+            return Err(format!("Cannot delete the active script {name:?}"));
+        }
+        self.db.remove_value(account, &Self::script_key(name))
+    }
+
+    fn rename_script(&self, account: &str, old_name: &str, new_name: &str) -> Result<(), String> {
+        let script = self.get_script(account, old_name)?;
+        self.db
+            .store_value(account, &Self::script_key(new_name), script)?;
+        self.db.remove_value(account, &Self::script_key(old_name))?;
+        if self.active_name(account)?.as_deref() == Some(old_name) {
+            self.db
+                .store_value(account, ACTIVE_NAME_KEY, new_name.as_bytes().to_vec())?;
+        }
+        Ok(())
+    }
+
+    fn have_space(&self, _account: &str, _name: &str, size: usize) -> Result<(), String> {
+        if size > self.max_script_size {
+            Err(format!(
+                "Script size {size} exceeds maximum of {} bytes",
+                self.max_script_size
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// `CredentialStore` backed by the same directory, verifying `PLAIN` SASL
+/// credentials against a `managesieve.secret` key on the account with a
+/// constant-time comparison.
+///
+/// This directory abstraction has no notion of password hashing, so this
+/// only ever compares raw bytes — storing plaintext passwords under
+/// `managesieve.secret` is not an acceptable production deployment.
+/// Directory backends that can verify hashed, salted credentials should
+/// implement `CredentialStore` directly instead of going through this
+/// adapter, which exists only so the subsystem is runnable without every
+/// backend reimplementing ManageSieve's wire-level SASL auth.
+pub struct DirectoryCredentialStore {
+    db: Arc<dyn ScriptDirectory>,
+}
+
+impl DirectoryCredentialStore {
+    pub fn new(db: Arc<dyn ScriptDirectory>) -> Self {
+        Self { db }
+    }
+}
+
+impl CredentialStore for DirectoryCredentialStore {
+    fn verify_plain(&self, authcid: &str, secret: &str) -> Result<Option<String>, String> {
+        match self.db.query_value(authcid, "managesieve.secret")? {
+            Some(stored) if constant_time_eq(&stored, secret.as_bytes()) => {
+                Ok(Some(authcid.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Compares two byte strings in time independent of where they first
+/// differ, so a sequence of failed `AUTHENTICATE` attempts can't be used to
+/// recover a stored secret one byte at a time via response timing.
+/// Short-circuiting `==` on the raw bytes would leak the shared prefix
+/// length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use crate::config::scripts::ExecuteProgram;
+
+/// Why a Sieve `execute "name" [...]` call was refused or failed, surfaced to
+/// the script as a runtime error.
+#[derive(Debug)]
+pub enum ExecuteError {
+    UnknownProgram(String),
+    Timeout,
+    DisallowedExitCode(i32),
+    Io(String),
+}
+
+impl std::fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecuteError::UnknownProgram(name) => {
+                write!(f, "Program {name:?} is not in the execute allowlist")
+            }
+            ExecuteError::Timeout => write!(f, "Program execution timed out"),
+            ExecuteError::DisallowedExitCode(code) => {
+                write!(f, "Program exited with disallowed status {code}")
+            }
+            ExecuteError::Io(err) => write!(f, "Failed to run program: {err}"),
+        }
+    }
+}
+
+/// Runs `name` from the `sieve.execute.programs` allowlist, piping `stdin`
+/// (the working message, or a named variable the script chose to pass
+/// instead) to it and returning its captured stdout.
+///
+/// Called synchronously from the Sieve runtime's `execute` handler (see
+/// `ConfigSieve::parse_sieve`), so this blocks the evaluating thread for up
+/// to `program.timeout` rather than returning a future — the interpreter has
+/// nothing useful to do until the program's output is available anyway.
+/// Unknown program names and exit codes outside `allowed_exit_codes` are
+/// rejected rather than silently tolerated, since this is the only gate
+/// between a script and an external process.
+pub fn execute(
+    programs: &HashMap<String, ExecuteProgram>,
+    name: &str,
+    args: &[String],
+    stdin: &[u8],
+) -> Result<Vec<u8>, ExecuteError> {
+    let program = programs
+        .get(name)
+        .ok_or_else(|| ExecuteError::UnknownProgram(name.to_string()))?;
+
+    let mut child = std::process::Command::new(&program.command)
+        .args(program.arguments.iter().chain(args.iter()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| ExecuteError::Io(err.to_string()))?;
+
+    let child_stdin = child.stdin.take();
+    let child_stdout = child.stdout.take();
+
+    // Stdin and stdout are both handled off the calling thread and
+    // concurrently with the wait loop. Writing stdin inline would deadlock
+    // against a full stdout pipe, since the child stops reading stdin while
+    // it is blocked writing output that nobody is reading yet; leaving
+    // stdout to `wait_with_output` has the symmetric bug, since that only
+    // reads stdout *after* the child is seen to exit via `try_wait` — a
+    // program that writes more than one pipe buffer (the scanner/rewriter
+    // use case this module exists for) fills the pipe, blocks forever, and
+    // is killed at `program.timeout` on every call. Draining stdout on its
+    // own thread removes that dependency on exit ordering entirely.
+    let (status, write_result, stdout_result) = std::thread::scope(|scope| {
+        let writer = child_stdin
+            .map(|mut child_stdin| scope.spawn(move || child_stdin.write_all(stdin)));
+        let reader = child_stdout.map(|mut child_stdout| {
+            scope.spawn(move || {
+                let mut buf = Vec::new();
+                child_stdout.read_to_end(&mut buf).map(|_| buf)
+            })
+        });
+
+        let status = wait_with_timeout(&mut child, program.timeout);
+
+        (
+            status,
+            writer.map(|writer| writer.join()),
+            reader.map(|reader| reader.join()),
+        )
+    });
+    let status = status?;
+
+    if let Some(write_result) = write_result {
+        // A broken pipe just means the program exited without reading all
+        // of stdin, which is fine; any other error is surfaced.
+        match write_result {
+            Ok(Err(err)) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(ExecuteError::Io(err.to_string()))
+            }
+            _ => {}
+        }
+    }
+
+    let stdout = match stdout_result {
+        Some(Ok(Ok(buf))) => buf,
+        Some(Ok(Err(err))) => return Err(ExecuteError::Io(err.to_string())),
+        Some(Err(_)) | None => Vec::new(),
+    };
+
+    let exit_code = status.code().unwrap_or(-1);
+    if !program.allowed_exit_codes.contains(&exit_code) {
+        return Err(ExecuteError::DisallowedExitCode(exit_code));
+    }
+
+    Ok(stdout)
+}
+
+/// Polls the child for completion, killing and reporting a timeout if it
+/// hasn't exited within `timeout`. `std::process::Child` has no built-in
+/// wait-with-timeout, so this drives `try_wait` by hand. Stdout is drained
+/// by a separate reader thread (see `execute`), so this only ever waits for
+/// the exit status.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Duration,
+) -> Result<std::process::ExitStatus, ExecuteError> {
+    let start = Instant::now();
+    loop {
+        match child.try_wait().map_err(|err| ExecuteError::Io(err.to_string()))? {
+            Some(status) => return Ok(status),
+            None if start.elapsed() >= timeout => {
+                let _ = child.kill();
+                // Reap the killed child so it doesn't linger as a zombie,
+                // but report the timeout itself rather than its (SIGKILL)
+                // exit status, which `allowed_exit_codes` was never meant to
+                // judge.
+                let _ = child.wait();
+                return Err(ExecuteError::Timeout);
+            }
+            None => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn allowlist(command: &str, timeout: Duration, allowed_exit_codes: Vec<i32>) -> HashMap<String, ExecuteProgram> {
+        let mut programs = HashMap::new();
+        programs.insert(
+            "test".to_string(),
+            ExecuteProgram {
+                command: PathBuf::from(command),
+                arguments: Vec::new(),
+                timeout,
+                allowed_exit_codes,
+            },
+        );
+        programs
+    }
+
+    #[test]
+    fn rejects_unknown_program() {
+        let programs = allowlist("/bin/cat", Duration::from_secs(1), vec![0]);
+        match execute(&programs, "nope", &[], b"hi") {
+            Err(ExecuteError::UnknownProgram(name)) => assert_eq!(name, "nope"),
+            other => panic!("expected UnknownProgram, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pipes_stdin_to_stdout() {
+        let programs = allowlist("/bin/cat", Duration::from_secs(1), vec![0]);
+        let output = execute(&programs, "test", &[], b"hello sieve").unwrap();
+        assert_eq!(output, b"hello sieve");
+    }
+
+    #[test]
+    fn rejects_disallowed_exit_code() {
+        let programs = allowlist("/bin/false", Duration::from_secs(1), vec![0]);
+        match execute(&programs, "test", &[], b"") {
+            Err(ExecuteError::DisallowedExitCode(code)) => assert_ne!(code, 0),
+            other => panic!("expected DisallowedExitCode, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn times_out_a_hanging_program() {
+        let programs = allowlist("/bin/sleep", Duration::from_millis(50), vec![0]);
+        match execute(&programs, "test", &["1".to_string()], b"") {
+            Err(ExecuteError::Timeout) => {}
+            other => panic!("expected Timeout, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn drains_stdout_larger_than_a_pipe_buffer_without_deadlocking() {
+        // Exercises the concurrent stdout reader: a naive "wait for exit,
+        // then read stdout" implementation deadlocks here once `cat` fills
+        // the stdout pipe before this much stdin has been written, and the
+        // test would hang until `program.timeout`.
+        let programs = allowlist("/bin/cat", Duration::from_secs(5), vec![0]);
+        let input = vec![b'a'; 1_000_000];
+        let output = execute(&programs, "test", &[], &input).unwrap();
+        assert_eq!(output, input);
+    }
+}